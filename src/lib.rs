@@ -1,37 +1,99 @@
 #[macro_use]
 extern crate log;
 
-use http::StatusCode;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use std::error::Error as _;
+use std::time::Duration;
 use std::{error, fmt};
 use warp::Rejection;
 
 pub type BoxedError = Box<dyn error::Error + Send + Sync + 'static>;
 
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+    message: Option<&'a str>,
+}
+
+#[derive(Debug)]
+enum ErrorSource {
+    Boxed(BoxedError),
+    #[cfg(feature = "anyhow")]
+    Anyhow(anyhow::Error),
+}
+
+impl ErrorSource {
+    fn as_error(&self) -> &(dyn error::Error + 'static) {
+        match self {
+            ErrorSource::Boxed(err) => err.as_ref(),
+            #[cfg(feature = "anyhow")]
+            ErrorSource::Anyhow(err) => &**err,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpError {
     status: StatusCode,
     message: Option<String>,
-    source: Option<BoxedError>,
+    source: Option<ErrorSource>,
+    headers: HeaderMap,
+    #[cfg(feature = "backtrace")]
+    backtrace: Option<std::backtrace::Backtrace>,
+}
+
+/// Like [`recover`], but also takes the request's `Accept` header so the
+/// reply body can be negotiated between `text/plain` and `application/json`
+/// (the latter only when the `json` feature is enabled). `warp`'s rejection
+/// handlers never see the original request, so the header has to be pulled
+/// out by the caller and threaded in here.
+pub async fn recover_with(
+    accept: Option<&str>,
+    err: warp::Rejection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(http_err) = err.find::<HttpError>() {
+        log_error(http_err);
+        return Ok(http_err.to_reply(accept));
+    }
+
+    if let Some(http_err) = HttpError::from_rejection(&err) {
+        log_error(&http_err);
+        return Ok(http_err.to_reply(accept));
+    }
+
+    Err(err)
+}
+
+fn log_error(err: &HttpError) {
+    error!("{}", err);
+    let mut source = err.source();
+    while let Some(err) = source {
+        error!("  -> {}", err);
+        source = err.source();
+    }
+
+    #[cfg(feature = "backtrace")]
+    if let Some(backtrace) = err.backtrace() {
+        debug!("{}", backtrace);
+    }
 }
 
 pub async fn recover(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
-    if let Some(ref err) = err.find::<HttpError>() {
-        error!("{}", err);
-        let mut source = err.source();
-        while let Some(err) = source {
-            error!("  -> {}", err);
-            source = err.source();
-        }
+    recover_with(None, err).await
+}
 
-        Ok(warp::reply::with_status(
-            err.message()
-                .unwrap_or_else(|| err.status().canonical_reason().unwrap_or(""))
-                .to_string(),
-            err.status(),
-        ))
-    } else {
-        Err(err)
+fn wants_json(_accept: Option<&str>) -> bool {
+    #[cfg(feature = "json")]
+    {
+        _accept
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false)
+    }
+    #[cfg(not(feature = "json"))]
+    {
+        false
     }
 }
 
@@ -41,20 +103,62 @@ impl HttpError {
             status,
             message: None,
             source: None,
+            headers: HeaderMap::new(),
+            // Capturing unconditionally for client errors is wasted work;
+            // 5xx responses are the ones worth diagnosing after the fact.
+            #[cfg(feature = "backtrace")]
+            backtrace: status
+                .is_server_error()
+                .then(std::backtrace::Backtrace::capture),
         }
     }
 
+    fn with_error_source(mut self, source: ErrorSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
     pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
         self.message = Some(message.into());
         self
     }
 
+    /// Attaches a response header, e.g. `Retry-After` on a 429/503 or
+    /// `WWW-Authenticate` on a 401. An invalid name or value is logged and
+    /// otherwise ignored rather than panicking the request.
+    pub fn with_header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        HeaderValue: TryFrom<V>,
+    {
+        match (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            (Ok(name), Ok(value)) => {
+                self.headers.insert(name, value);
+            }
+            _ => warn!("ignoring invalid HttpError response header"),
+        }
+        self
+    }
+
+    pub fn with_retry_after(self, duration: Duration) -> Self {
+        self.with_header(http::header::RETRY_AFTER, duration.as_secs().to_string())
+    }
+
     pub fn with_source(
-        mut self,
+        self,
         source: impl Into<Box<dyn error::Error + Send + Sync + 'static>>,
     ) -> Self {
-        self.source = Some(source.into());
-        self
+        self.with_error_source(ErrorSource::Boxed(source.into()))
+    }
+
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<&std::backtrace::Backtrace> {
+        self.backtrace.as_ref()
+    }
+
+    #[cfg(feature = "anyhow")]
+    pub fn with_err_anyhow(self, source: anyhow::Error) -> Self {
+        self.with_error_source(ErrorSource::Anyhow(source))
     }
 
     pub fn status(&self) -> StatusCode {
@@ -62,7 +166,81 @@ impl HttpError {
     }
 
     pub fn message(&self) -> Option<&str> {
-        self.message.as_ref().map(|s| &**s)
+        self.message.as_deref()
+    }
+
+    /// Translates one of warp's built-in rejections (method not allowed,
+    /// missing/invalid header, oversized or undeserializable body, ...)
+    /// into an `HttpError` with the matching status, so `recover` can treat
+    /// them the same way it treats custom `HttpError` rejections.
+    pub fn from_rejection(err: &Rejection) -> Option<HttpError> {
+        if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+            return Some(HttpError::new(StatusCode::METHOD_NOT_ALLOWED).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+            return Some(HttpError::new(StatusCode::BAD_REQUEST).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+            return Some(HttpError::new(StatusCode::BAD_REQUEST).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+            return Some(HttpError::new(StatusCode::BAD_REQUEST).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+            return Some(HttpError::new(StatusCode::BAD_REQUEST).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+            return Some(HttpError::new(StatusCode::LENGTH_REQUIRED).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+            return Some(HttpError::new(StatusCode::PAYLOAD_TOO_LARGE).with_message(e.to_string()));
+        }
+
+        if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+            return Some(
+                HttpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE).with_message(e.to_string()),
+            );
+        }
+
+        None
+    }
+
+    fn to_reply(&self, accept: Option<&str>) -> warp::reply::Response {
+        use warp::Reply as _;
+
+        let reply = if wants_json(accept) {
+            #[cfg(feature = "json")]
+            {
+                let body = ErrorBody {
+                    status: self.status.as_u16(),
+                    error: self.status.canonical_reason().unwrap_or(""),
+                    message: self.message(),
+                };
+                warp::reply::with_status(warp::reply::json(&body), self.status).into_response()
+            }
+            #[cfg(not(feature = "json"))]
+            {
+                unreachable!()
+            }
+        } else {
+            warp::reply::with_status(
+                self.message()
+                    .unwrap_or_else(|| self.status.canonical_reason().unwrap_or(""))
+                    .to_string(),
+                self.status,
+            )
+            .into_response()
+        };
+
+        self.headers.iter().fold(reply, |reply, (name, value)| {
+            warp::reply::with_header(reply, name.clone(), value.clone()).into_response()
+        })
     }
 }
 
@@ -90,11 +268,9 @@ where
 {
     fn with_err_status(self, status: StatusCode) -> Result<T, warp::Rejection> {
         self.map_err(|err| {
-            warp::reject::custom(HttpError {
-                status,
-                message: None,
-                source: Some(err.into()),
-            })
+            warp::reject::custom(
+                HttpError::new(status).with_error_source(ErrorSource::Boxed(err.into())),
+            )
         })
     }
 
@@ -104,15 +280,95 @@ where
         message: F,
     ) -> Result<T, warp::Rejection> {
         self.map_err(|err| {
-            warp::reject::custom(HttpError {
-                status,
-                message: Some(message()),
-                source: Some(err.into()),
-            })
+            warp::reject::custom(
+                HttpError::new(status)
+                    .with_message(message())
+                    .with_error_source(ErrorSource::Boxed(err.into())),
+            )
+        })
+    }
+}
+
+/// Like [`ResultExt`], but for `anyhow::Result` and keeping the `anyhow`
+/// context chain intact. This can't just be another `ResultExt` impl for
+/// `Result<T, anyhow::Error>`: `anyhow` ships its own
+/// `From<anyhow::Error> for Box<dyn Error + Send + Sync>`, so `anyhow::Error`
+/// already satisfies the blanket impl's bound and a second impl would
+/// conflict with it.
+#[cfg(feature = "anyhow")]
+pub trait AnyhowResultExt<T>: Sized {
+    fn with_err_status_anyhow(self, status: StatusCode) -> Result<T, warp::Rejection>;
+
+    fn with_err_msg_anyhow<F: FnOnce() -> String>(
+        self,
+        status: StatusCode,
+        message: F,
+    ) -> Result<T, warp::Rejection>;
+}
+
+#[cfg(feature = "anyhow")]
+impl<T> AnyhowResultExt<T> for std::result::Result<T, anyhow::Error> {
+    fn with_err_status_anyhow(self, status: StatusCode) -> Result<T, warp::Rejection> {
+        self.map_err(|err| warp::reject::custom(HttpError::new(status).with_err_anyhow(err)))
+    }
+
+    fn with_err_msg_anyhow<F: FnOnce() -> String>(
+        self,
+        status: StatusCode,
+        message: F,
+    ) -> Result<T, warp::Rejection> {
+        self.map_err(|err| {
+            warp::reject::custom(
+                HttpError::new(status)
+                    .with_message(message())
+                    .with_err_anyhow(err),
+            )
         })
     }
 }
 
+/// Implemented by domain error enums that know their own HTTP status, so
+/// they can convert straight into an [`HttpError`] instead of every call
+/// site having to pick a status with `.client_err()`/`.server_err()`.
+pub trait ResponseError {
+    fn status(&self) -> StatusCode;
+
+    fn message(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<E> From<E> for HttpError
+where
+    E: ResponseError + error::Error + Send + Sync + 'static,
+{
+    fn from(err: E) -> Self {
+        let status = err.status();
+        let message = err.message();
+        let http_err = HttpError::new(status).with_source(err);
+        match message {
+            Some(message) => http_err.with_message(message),
+            None => http_err,
+        }
+    }
+}
+
+/// Extension for converting a `Result<T, E>` straight into a
+/// `Result<T, Rejection>` using `E`'s own [`ResponseError`] status, rather
+/// than forcing a single hard-coded status via [`ResultExt`].
+pub trait ResponseErrorExt<T>: Sized {
+    fn err_response(self) -> Result<T, warp::Rejection>;
+}
+
+impl<T, E> ResponseErrorExt<T> for std::result::Result<T, E>
+where
+    E: ResponseError + error::Error + Send + Sync + 'static,
+{
+    fn err_response(self) -> Result<T, warp::Rejection> {
+        self.map_err(|err| warp::reject::custom(HttpError::from(err)))
+    }
+}
+
 pub fn status(status: StatusCode) -> HttpError {
     HttpError::new(status)
 }
@@ -129,6 +385,15 @@ pub fn internal_server_error(err: impl error::Error + Send + Sync + 'static) ->
     HttpError::new(StatusCode::INTERNAL_SERVER_ERROR).with_source(err)
 }
 
+#[cfg(feature = "anyhow")]
+pub fn internal_server_error_anyhow(err: anyhow::Error) -> HttpError {
+    HttpError::new(StatusCode::INTERNAL_SERVER_ERROR).with_err_anyhow(err)
+}
+
+pub fn unauthorized() -> HttpError {
+    HttpError::new(StatusCode::UNAUTHORIZED)
+}
+
 impl warp::reject::Reject for HttpError {}
 
 impl fmt::Display for HttpError {
@@ -139,15 +404,7 @@ impl fmt::Display for HttpError {
 
 impl error::Error for HttpError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        self.source
-            .as_ref()
-            .map(|err| err.as_ref() as &(dyn error::Error + 'static))
-    }
-}
-
-impl From<HttpError> for Rejection {
-    fn from(err: HttpError) -> Self {
-        warp::reject::custom(err)
+        self.source.as_ref().map(ErrorSource::as_error)
     }
 }
 
@@ -189,3 +446,60 @@ macro_rules! forbidden {
         ::warp::reject::custom($crate::HttpError::new(::http::StatusCode::FORBIDDEN).with_message(format!($fmt, $($arg)+)))
     });
 }
+
+#[macro_export]
+macro_rules! unauthorized {
+    () => ({
+        ::warp::reject::custom($crate::HttpError::new(::http::StatusCode::UNAUTHORIZED))
+    });
+    ($msg:expr) => ({
+        ::warp::reject::custom($crate::HttpError::new(::http::StatusCode::UNAUTHORIZED).with_message($msg))
+    });
+    ($msg:expr, challenge = $challenge:expr) => ({
+        ::warp::reject::custom(
+            $crate::HttpError::new(::http::StatusCode::UNAUTHORIZED)
+                .with_message($msg)
+                .with_header(::http::header::WWW_AUTHENTICATE, $challenge),
+        )
+    });
+    ($fmt:expr, $($arg:tt)+) => ({
+        ::warp::reject::custom($crate::HttpError::new(::http::StatusCode::UNAUTHORIZED).with_message(format!($fmt, $($arg)+)))
+    });
+}
+
+/// Generates a free constructor function plus a `$crate`-exported macro
+/// (with the same `()`, `($msg:expr)`, and `($fmt, $args)` arms as the
+/// hand-written macros above) for one HTTP status, so the rarer codes
+/// don't need their boilerplate written out by hand. `$d` must be passed
+/// the literal `$` token at the call site — it's the standard way to get
+/// a `$` into the body of a macro a macro generates.
+macro_rules! define_http_error {
+    ($d:tt $fn_name:ident, $macro_name:ident, $status:expr) => {
+        pub fn $fn_name() -> HttpError {
+            HttpError::new($status)
+        }
+
+        #[macro_export]
+        macro_rules! $macro_name {
+            () => {
+                ::warp::reject::custom($crate::HttpError::new($status))
+            };
+            ($d msg:expr) => {
+                ::warp::reject::custom($crate::HttpError::new($status).with_message($d msg))
+            };
+            ($d fmt:expr, $d($d arg:tt)+) => {
+                ::warp::reject::custom(
+                    $crate::HttpError::new($status).with_message(format!($d fmt, $d($d arg)+)),
+                )
+            };
+        }
+    };
+}
+
+define_http_error!($ conflict, conflict, StatusCode::CONFLICT);
+define_http_error!($ gone, gone, StatusCode::GONE);
+define_http_error!($ unprocessable_entity, unprocessable_entity, StatusCode::UNPROCESSABLE_ENTITY);
+define_http_error!($ too_many_requests, too_many_requests, StatusCode::TOO_MANY_REQUESTS);
+define_http_error!($ service_unavailable, service_unavailable, StatusCode::SERVICE_UNAVAILABLE);
+define_http_error!($ bad_gateway, bad_gateway, StatusCode::BAD_GATEWAY);
+define_http_error!($ not_implemented, not_implemented, StatusCode::NOT_IMPLEMENTED);